@@ -0,0 +1,125 @@
+use std::collections::{ HashMap, VecDeque };
+use std::hash::Hash;
+
+/// A measure of how much room a cached value takes up, used to bound the
+/// total size of a [`Cache`] independently of its entry count.
+pub trait Weight {
+    fn weight(&self) -> usize;
+}
+
+/// A least-recently-used cache bounded by both entry count and total
+/// weight. Inserting past either limit evicts the least-recently-used
+/// entries until both are satisfied again.
+pub struct Cache<K, V> {
+    entries: HashMap<K, V>,
+    order: VecDeque<K>,
+    max_entries: usize,
+    max_weight: usize,
+    total_weight: usize
+}
+
+impl<K, V> Cache<K, V>
+where
+    K: Eq + Hash + Clone,
+    V: Weight
+{
+    pub fn new(max_entries: usize, max_weight: usize) -> Cache<K, V> {
+        Cache {
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            max_entries,
+            max_weight,
+            total_weight: 0
+        }
+    }
+
+    pub fn total_weight(&self) -> usize {
+        self.total_weight
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<&V> where V: Clone {
+        if !self.entries.contains_key(key) {
+            return None;
+        }
+        self.touch(key);
+        self.entries.get(key)
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        if let Some(old) = self.entries.remove(&key) {
+            self.total_weight -= old.weight();
+            self.order.retain(|k| k != &key);
+        }
+
+        self.total_weight += value.weight();
+        self.entries.insert(key.clone(), value);
+        self.order.push_back(key);
+        self.evict();
+    }
+
+    fn touch(&mut self, key: &K) {
+        if let Some(pos) = self.order.iter().position(|k| k == key) {
+            let key = self.order.remove(pos).expect("position just found");
+            self.order.push_back(key);
+        }
+    }
+
+    fn evict(&mut self) {
+        while self.entries.len() > self.max_entries || self.total_weight > self.max_weight {
+            let lru = match self.order.pop_front() {
+                Some(key) => key,
+                None => break
+            };
+            if let Some(value) = self.entries.remove(&lru) {
+                self.total_weight -= value.weight();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl Weight for u32 {
+        fn weight(&self) -> usize {
+            *self as usize
+        }
+    }
+
+    #[test]
+    fn evicts_lru_entry_past_max_entries() {
+        let mut cache: Cache<&str, u32> = Cache::new(2, usize::max_value());
+        cache.insert("a", 1);
+        cache.insert("b", 1);
+        cache.insert("c", 1);
+
+        assert_eq!(cache.get(&"a"), None);
+        assert_eq!(cache.get(&"b"), Some(&1));
+        assert_eq!(cache.get(&"c"), Some(&1));
+    }
+
+    #[test]
+    fn evicts_lru_entries_past_max_weight() {
+        let mut cache: Cache<&str, u32> = Cache::new(usize::max_value(), 10);
+        cache.insert("a", 6);
+        cache.insert("b", 6);
+
+        assert_eq!(cache.get(&"a"), None);
+        assert_eq!(cache.get(&"b"), Some(&6));
+        assert_eq!(cache.total_weight(), 6);
+    }
+
+    #[test]
+    fn get_refreshes_recency_so_it_is_not_the_next_eviction() {
+        let mut cache: Cache<&str, u32> = Cache::new(2, usize::max_value());
+        cache.insert("a", 1);
+        cache.insert("b", 1);
+        cache.get(&"a");
+        cache.insert("c", 1);
+
+        assert_eq!(cache.get(&"a"), Some(&1));
+        assert_eq!(cache.get(&"b"), None);
+        assert_eq!(cache.get(&"c"), Some(&1));
+    }
+}
@@ -1,136 +1,474 @@
 mod graph;
+mod cache;
 
 use std::mem;
+use std::cmp::Ordering;
 use std::ops::Add;
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicBool, Ordering as AtomicOrdering };
 use std::hash::{ Hash, BuildHasher };
 use std::vec::IntoIter;
+use std::collections::{ VecDeque, HashMap, BinaryHeap };
 use std::collections::hash_map::RandomState;
 use futures::stream::futures_unordered::FuturesUnordered;
 use futures::sync::{oneshot, BiLock};
 use futures::prelude::*;
 use crate::graph::Graph;
 pub use crate::graph::Index;
-
-
-pub struct TaskGraph<T, I=u32, S=RandomState> {
-    dag: Graph<State<T>, I, S>,
-    pending: Vec<IndexFuture<T, I>>
+pub use crate::cache::Weight;
+use crate::cache::Cache;
+
+
+pub struct TaskGraph<T: Future, I=u32, S=RandomState, K=()> {
+    dag: Graph<State<T, I>, I, S>,
+    pending: Vec<IndexFuture<T, I>>,
+    resolved: Vec<(Index<I>, T::Item)>,
+    // One flag per node still live in the dag, shared with the `IndexFuture`
+    // it eventually becomes so that cancelling a running node can actually
+    // drop its in-flight future instead of just discarding its output.
+    cancel_flags: HashMap<Index<I>, Arc<AtomicBool>>,
+    // `Arc<Mutex<_>>` rather than an owned `Cache` so a cache can be built
+    // once and handed to several `TaskGraph`s in turn via
+    // [`with_shared_cache`](#method.with_shared_cache) — letting hits from
+    // one run of `execute` short-circuit recomputation in the next.
+    cache: Option<Arc<Mutex<Cache<K, T::Item>>>>,
+    keys: HashMap<Index<I>, K>,
+    // Completed (key, output) pairs waiting to be folded into `cache`. Kept
+    // separate from `cache` itself so that recording a completion (done from
+    // the plain `Execute` poll loop) never needs `T::Item: Weight` — only
+    // draining them back into `cache`, which happens from the `*_memoized`
+    // constructors, does.
+    pending_inserts: Vec<(K, T::Item)>
 }
 
-enum State<T> {
+type Builder<T> = Box<dyn FnOnce(Vec<<T as Future>::Item>) -> T>;
+
+enum State<T: Future, I> {
     Pending {
         count: usize,
-        task: T
+        priority: u32,
+        order: Vec<Index<I>>,
+        inputs: Vec<Option<T::Item>>,
+        builder: Builder<T>,
+        cancel: Arc<AtomicBool>
     },
     Running,
 }
 
-impl<T, I, S> Default for TaskGraph<T, I, S>
+impl<T, I, S, K> Default for TaskGraph<T, I, S, K>
 where
+    T: Future,
     I: Default + Hash + PartialEq + Eq,
     S: Default + BuildHasher
 {
-    fn default() -> TaskGraph<T, I, S> {
-        TaskGraph { dag: Graph::default(), pending: Vec::new() }
+    fn default() -> TaskGraph<T, I, S, K> {
+        TaskGraph {
+            dag: Graph::default(),
+            pending: Vec::new(),
+            resolved: Vec::new(),
+            cancel_flags: HashMap::new(),
+            cache: None,
+            keys: HashMap::new(),
+            pending_inserts: Vec::new()
+        }
     }
 }
 
-impl<T> TaskGraph<T> {
+impl<T: Future> TaskGraph<T> {
     pub fn new() -> Self {
         TaskGraph::default()
     }
 }
 
-impl<T, I, S> TaskGraph<T, I, S>
+impl<T, I, S, K> TaskGraph<T, I, S, K>
 where
     T: Future,
+    T::Item: Clone,
     for<'a> &'a I: Add<I>,
     for<'a> <&'a I as Add<I>>::Output: Into<I>,
     I: From<u32> + Hash + PartialEq + Eq + Clone,
     S: BuildHasher
 {
     pub fn add_task(&mut self, deps: &[Index<I>], task: T) -> Index<I> {
-        if deps.is_empty() {
+        self.add_task_with(deps, move |_| task)
+    }
+
+    /// Like [`add_task`](#method.add_task), but instead of an already-built
+    /// future, takes a `builder` that is called with the collected outputs
+    /// of this node's direct dependencies (in the order `deps` was given)
+    /// once all of them have completed. This threads data between tasks
+    /// instead of just ordering them.
+    pub fn add_task_with<B>(&mut self, deps: &[Index<I>], builder: B) -> Index<I>
+    where
+        B: FnOnce(Vec<T::Item>) -> T + 'static
+    {
+        self.add_task_inner(deps, 0, builder)
+    }
+
+    /// Like [`add_task`](#method.add_task), but assigns the node a
+    /// `priority`: when multiple tasks become ready at once and the graph is
+    /// running under [`execute_bounded`](#method.execute_bounded), the
+    /// highest-priority ready task is admitted into the polling set first.
+    pub fn add_task_prioritized(&mut self, deps: &[Index<I>], priority: u32, task: T) -> Index<I> {
+        self.add_task_with_prioritized(deps, priority, move |_| task)
+    }
+
+    /// Combines [`add_task_with`](#method.add_task_with) and
+    /// [`add_task_prioritized`](#method.add_task_prioritized).
+    pub fn add_task_with_prioritized<B>(&mut self, deps: &[Index<I>], priority: u32, builder: B) -> Index<I>
+    where
+        B: FnOnce(Vec<T::Item>) -> T + 'static
+    {
+        self.add_task_inner(deps, priority, builder)
+    }
+
+    /// Enables a bounded LRU cache on this graph: completed task outputs
+    /// are kept around (up to `max_entries` entries and `max_weight` total
+    /// [`Weight`]) so that [`add_task_memoized`](#method.add_task_memoized)
+    /// can short-circuit recomputation of the same `key`. The cache lives
+    /// only as long as this graph's own run; to share one cache between
+    /// several graphs, build it once and install it on each graph with
+    /// [`with_shared_cache`](#method.with_shared_cache) instead.
+    pub fn with_cache(self, max_entries: usize, max_weight: usize) -> Self
+    where
+        K: Eq + Hash + Clone,
+        T::Item: Weight
+    {
+        self.with_shared_cache(Arc::new(Mutex::new(Cache::new(max_entries, max_weight))))
+    }
+
+    /// Like [`with_cache`](#method.with_cache), but installs a cache built
+    /// ahead of time instead of a fresh one, so the same `Arc<Mutex<Cache>>`
+    /// can be shared across a whole sequence of graphs. A graph's completed
+    /// outputs reach the shared cache only when they are folded in by a
+    /// later `add_task_with_memoized` call on that same graph (or its
+    /// [`AddTask`]); outputs still unfolded when the graph is dropped are
+    /// not cached for the next run.
+    pub fn with_shared_cache(mut self, cache: Arc<Mutex<Cache<K, T::Item>>>) -> Self
+    where
+        K: Eq + Hash + Clone,
+        T::Item: Weight
+    {
+        self.cache = Some(cache);
+        self
+    }
+
+    pub fn add_task_memoized(&mut self, deps: &[Index<I>], key: K, task: T) -> Index<I>
+    where
+        K: Eq + Hash + Clone,
+        T::Item: Weight
+    {
+        self.add_task_with_memoized(deps, key, move |_| task)
+    }
+
+    /// Like [`add_task_with`](#method.add_task_with), but first checks the
+    /// cache installed via [`with_cache`](#method.with_cache) for `key`. On
+    /// a hit, the node is marked done right away with the cached output and
+    /// `builder` is never called; on a miss, the eventual output is
+    /// recorded under `key` once the task completes and inserted into the
+    /// cache the next time `add_task_with_memoized` is called on this graph.
+    pub fn add_task_with_memoized<B>(&mut self, deps: &[Index<I>], key: K, builder: B) -> Index<I>
+    where
+        K: Eq + Hash + Clone,
+        T::Item: Weight,
+        B: FnOnce(Vec<T::Item>) -> T + 'static
+    {
+        self.drain_inserts();
+
+        if let Some(value) = self.cache_get(&key) {
             let index = self.dag.add_node(State::Running);
-            self.pending.push(IndexFuture::new(index.clone(), task));
+            self.resolved.push((index.clone(), value));
+            return index;
+        }
+
+        let index = self.add_task_inner(deps, 0, builder);
+        self.keys.insert(index.clone(), key);
+        index
+    }
+
+    fn cache_get(&self, key: &K) -> Option<T::Item>
+    where
+        K: Eq + Hash + Clone,
+        T::Item: Weight
+    {
+        self.cache.as_ref().and_then(|cache| {
+            cache.lock().expect("cache mutex poisoned").get(key).cloned()
+        })
+    }
+
+    fn add_task_inner<B>(&mut self, deps: &[Index<I>], priority: u32, builder: B) -> Index<I>
+    where
+        B: FnOnce(Vec<T::Item>) -> T + 'static
+    {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let index = if deps.is_empty() {
+            let index = self.dag.add_node(State::Running);
+            self.pending.push(IndexFuture::new(index.clone(), priority, cancel.clone(), builder(Vec::new())));
             index
         } else {
-            let index = self.dag.add_node(State::Pending { count: deps.len(), task });
+            let state = State::Pending {
+                count: deps.len(),
+                priority,
+                order: deps.to_vec(),
+                inputs: vec![None; deps.len()],
+                builder: Box::new(builder),
+                cancel: cancel.clone()
+            };
+            let index = self.dag.add_node(state);
             for parent in deps {
                 self.dag.add_edge(parent, index.clone());
             }
             index
+        };
+        self.cancel_flags.insert(index.clone(), cancel);
+        index
+    }
+
+    /// Folds any completions recorded by a running [`Execute`] since the
+    /// last call into `cache`.
+    fn drain_inserts(&mut self)
+    where
+        K: Eq + Hash + Clone,
+        T::Item: Weight
+    {
+        if let Some(cache) = self.cache.as_ref() {
+            let mut cache = cache.lock().expect("cache mutex poisoned");
+            for (key, value) in self.pending_inserts.drain(..) {
+                cache.insert(key, value);
+            }
         }
     }
 
-    pub fn execute(mut self) -> (AddTask<T, I, S>, Execute<T, I, S>) {
+    pub fn execute(self) -> (AddTask<T, I, S, K>, Execute<T, I, S, K>) {
+        self.execute_bounded(usize::max_value())
+    }
+
+    /// Like [`execute`](#method.execute), but caps the number of `T`s polled
+    /// concurrently at `limit`. Tasks that become ready while the cap is
+    /// reached wait in an internal backlog and are admitted into the poll
+    /// set as running tasks complete. A `limit` of `0` would never admit
+    /// anything and hang the stream forever, so it is clamped to `1`.
+    pub fn execute_bounded(mut self, limit: usize) -> (AddTask<T, I, S, K>, Execute<T, I, S, K>) {
+        let limit = limit.max(1);
         let mut queue = FuturesUnordered::new();
+        let mut backlog = BinaryHeap::new();
         for fut in self.pending.drain(..) {
-            queue.push(fut);
+            backlog.push(fut);
+        }
+        while queue.len() < limit {
+            match backlog.pop() {
+                Some(fut) => queue.push(fut),
+                None => break
+            }
         }
+        // Cache hits recorded before `execute` stay in `resolved` so that the
+        // first `enqueue` reports them as `Outcome::Done` like any other.
+        let done = Vec::new();
         let (g1, g2) = BiLock::new(self);
         let (tx, rx) = oneshot::channel();
         (
             AddTask { inner: g1, tx },
-            Execute { inner: g2, done: Vec::new(), queue, rx }
+            Execute { inner: g2, done, queue, backlog, limit, outbox: VecDeque::new(), pending_fail: None, rx }
         )
     }
 
-    fn walk(&mut self, index: &Index<I>) -> TaskWalker<'_, T, I, S> {
+    fn walk(&mut self, index: &Index<I>, item: T::Item) -> TaskWalker<'_, T, I, S> {
         let walker = self.dag.walk(index);
-        TaskWalker { dag: &mut self.dag, walker }
+        TaskWalker { dag: &mut self.dag, walker, parent: index.clone(), item }
     }
 }
 
-pub struct AddTask<T, I=u32, S=RandomState> {
-    inner: BiLock<TaskGraph<T, I, S>>,
+pub struct AddTask<T: Future, I=u32, S=RandomState, K=()> {
+    inner: BiLock<TaskGraph<T, I, S, K>>,
     tx: oneshot::Sender<()>
 }
 
-impl<T, I, S> AddTask<T, I, S>
+impl<T, I, S, K> AddTask<T, I, S, K>
 where
+    T: Future,
+    T::Item: Clone,
     for<'a> &'a I: Add<I>,
     for<'a> <&'a I as Add<I>>::Output: Into<I>,
     I: From<u32> + Hash + PartialEq + Eq + Clone,
     S: BuildHasher
 {
     pub fn add_task(&self, deps: &[Index<I>], task: T) -> Async<Index<I>> {
+        self.add_task_with(deps, move |_| task)
+    }
+
+    /// Like [`add_task`](#method.add_task), but takes a `builder` that is
+    /// called with the collected outputs of this node's direct dependencies
+    /// once all of them have completed. Dependencies that are no longer in
+    /// the graph when this is called (already completed, failed or
+    /// cancelled) are not waited on, and their outputs are not among the
+    /// inputs.
+    pub fn add_task_with<B>(&self, deps: &[Index<I>], builder: B) -> Async<Index<I>>
+    where
+        B: FnOnce(Vec<T::Item>) -> T + 'static
+    {
+        self.add_task_with_prioritized(deps, 0, builder)
+    }
+
+    /// Like [`add_task`](#method.add_task), but assigns the node a
+    /// `priority`, mirroring [`TaskGraph::add_task_prioritized`].
+    pub fn add_task_prioritized(&self, deps: &[Index<I>], priority: u32, task: T) -> Async<Index<I>> {
+        self.add_task_with_prioritized(deps, priority, move |_| task)
+    }
+
+    /// Combines [`add_task_with`](#method.add_task_with) and
+    /// [`add_task_prioritized`](#method.add_task_prioritized).
+    pub fn add_task_with_prioritized<B>(&self, deps: &[Index<I>], priority: u32, builder: B) -> Async<Index<I>>
+    where
+        B: FnOnce(Vec<T::Item>) -> T + 'static
+    {
         let mut graph = match self.inner.poll_lock() {
             Async::Ready(graph) => graph,
             Async::NotReady => return Async::NotReady
         };
 
-        let count = deps.iter()
+        let cancel = Arc::new(AtomicBool::new(false));
+        let order: Vec<Index<I>> = deps.iter()
             .filter(|&i| graph.dag.contains(i))
-            .count();
-        if count == 0 {
+            .cloned()
+            .collect();
+        let index = if order.is_empty() {
             let index = graph.dag.add_node(State::Running);
-            graph.pending.push(IndexFuture::new(index.clone(), task));
-            Async::Ready(index)
+            graph.pending.push(IndexFuture::new(index.clone(), priority, cancel.clone(), builder(Vec::new())));
+            index
         } else {
-            let index = graph.dag.add_node(State::Pending { count, task });
+            let state = State::Pending {
+                count: order.len(),
+                priority,
+                inputs: vec![None; order.len()],
+                order,
+                builder: Box::new(builder),
+                cancel: cancel.clone()
+            };
+            let index = graph.dag.add_node(state);
             for parent in deps {
                 graph.dag.add_edge(parent, index.clone());
             }
-            Async::Ready(index)
+            index
+        };
+        graph.cancel_flags.insert(index.clone(), cancel);
+        Async::Ready(index)
+    }
+
+    /// Like [`add_task_with`](#method.add_task_with), but first checks the
+    /// cache installed via [`TaskGraph::with_cache`] for `key`, mirroring
+    /// [`TaskGraph::add_task_with_memoized`].
+    pub fn add_task_with_memoized<B>(&self, deps: &[Index<I>], key: K, builder: B) -> Async<Index<I>>
+    where
+        K: Eq + Hash + Clone,
+        T::Item: Weight,
+        B: FnOnce(Vec<T::Item>) -> T + 'static
+    {
+        let mut graph = match self.inner.poll_lock() {
+            Async::Ready(graph) => graph,
+            Async::NotReady => return Async::NotReady
+        };
+
+        graph.drain_inserts();
+
+        if let Some(value) = graph.cache_get(&key) {
+            let index = graph.dag.add_node(State::Running);
+            graph.resolved.push((index.clone(), value));
+            return Async::Ready(index);
         }
+
+        let cancel = Arc::new(AtomicBool::new(false));
+        let order: Vec<Index<I>> = deps.iter()
+            .filter(|&i| graph.dag.contains(i))
+            .cloned()
+            .collect();
+        let index = if order.is_empty() {
+            let index = graph.dag.add_node(State::Running);
+            graph.pending.push(IndexFuture::new(index.clone(), 0, cancel.clone(), builder(Vec::new())));
+            index
+        } else {
+            let state = State::Pending {
+                count: order.len(),
+                priority: 0,
+                inputs: vec![None; order.len()],
+                order,
+                builder: Box::new(builder),
+                cancel: cancel.clone()
+            };
+            let index = graph.dag.add_node(state);
+            for parent in deps {
+                graph.dag.add_edge(parent, index.clone());
+            }
+            index
+        };
+
+        graph.cancel_flags.insert(index.clone(), cancel);
+        graph.keys.insert(index.clone(), key);
+        Async::Ready(index)
     }
 
     pub fn abort(self) {
         let _ = self.tx.send(());
     }
+
+    /// Cancels the subtree rooted at `index`: the node itself and every
+    /// descendant reachable through it are removed from the dag so they
+    /// never become ready. A node that is already running is flagged so its
+    /// in-flight future is dropped the next time it is polled, rather than
+    /// being run to completion only to have its output discarded — but this
+    /// is best-effort: the flag is only checked when `queue` polls the
+    /// future, so one that is parked on I/O and never otherwise woken stays
+    /// parked (and holding its resources) until something else causes it to
+    /// be polled again. Sibling branches keep running untouched.
+    pub fn cancel(&self, index: &Index<I>) -> Async<()> {
+        let mut graph = match self.inner.poll_lock() {
+            Async::Ready(graph) => graph,
+            Async::NotReady => return Async::NotReady
+        };
+
+        let mut frontier = vec![index.clone()];
+        while let Some(idx) = frontier.pop() {
+            if !graph.dag.contains(&idx) {
+                continue;
+            }
+            frontier.extend(graph.dag.walk(&idx));
+            graph.dag.remove_node(&idx);
+            if let Some(cancel) = graph.cancel_flags.remove(&idx) {
+                cancel.store(true, AtomicOrdering::Relaxed);
+            }
+        }
+
+        Async::Ready(())
+    }
+}
+
+/// The per-node result of an [`Execute`] stream: a task either completes
+/// with its output, fails with its error, or is reported as skipped because
+/// an ancestor of it failed.
+pub enum Outcome<I, F: Future> {
+    Done(Index<I>, F::Item),
+    Failed(Index<I>, F::Error),
+    Skipped(Vec<Index<I>>)
 }
 
-pub struct Execute<T, I=u32, S=RandomState> {
-    inner: BiLock<TaskGraph<T, I, S>>,
+pub struct Execute<T: Future, I=u32, S=RandomState, K=()> {
+    inner: BiLock<TaskGraph<T, I, S, K>>,
     queue: FuturesUnordered<IndexFuture<T, I>>,
-    done: Vec<Index<I>>,
+    backlog: BinaryHeap<IndexFuture<T, I>>,
+    limit: usize,
+    done: Vec<(Index<I>, T::Item)>,
+    outbox: VecDeque<Outcome<I, T>>,
+    // A failure whose isolation (`fail`) couldn't run yet because the graph
+    // lock was contended; retried on every poll until it actually runs, so
+    // `Outcome::Failed` is never reported before its descendants are
+    // isolated and queued up as `Skipped`.
+    pending_fail: Option<(Index<I>, T::Error)>,
     rx: oneshot::Receiver<()>
 }
 
-impl<T, I, S> Execute<T, I, S>
+impl<T, I, S, K> Execute<T, I, S, K>
 where
     T: Future,
+    T::Item: Clone,
     for<'a> &'a I: Add<I>,
     for<'a> <&'a I as Add<I>>::Output: Into<I>,
     I: From<u32> + Hash + PartialEq + Eq + Clone,
@@ -143,30 +481,123 @@ where
         };
 
         for fut in graph.pending.drain(..) {
-            self.queue.push(fut);
+            self.backlog.push(fut);
         }
 
-        for index in self.done.drain(..) {
-            for fut in graph.walk(&index) {
-                self.queue.push(fut);
+        // Nodes can resolve straight from the cache (no future involved)
+        // when they are added; such a node never passes through `queue`, so
+        // report it here instead of relying on the queue-completion arm in
+        // `Stream::poll`. Walking completed nodes only builds futures for
+        // their children, so the loop ends once `done` is drained.
+        loop {
+            let newly_resolved: Vec<_> = graph.resolved.drain(..).collect();
+            for (index, item) in &newly_resolved {
+                self.outbox.push_back(Outcome::Done(index.clone(), item.clone()));
+            }
+            self.done.extend(newly_resolved);
+
+            if self.done.is_empty() {
+                break;
+            }
+            for (index, item) in self.done.drain(..) {
+                for fut in graph.walk(&index, item) {
+                    self.backlog.push(fut);
+                }
+                graph.dag.remove_node(&index);
+                graph.cancel_flags.remove(&index);
+            }
+        }
+
+        while self.queue.len() < self.limit {
+            match self.backlog.pop() {
+                // A future cancelled while it was still sitting in the
+                // backlog is dropped here rather than ever being polled.
+                Some(fut) if fut.cancel.load(AtomicOrdering::Relaxed) => (),
+                Some(fut) => self.queue.push(fut),
+                None => break
             }
-            graph.dag.remove_node(&index);
         }
 
         Async::Ready(())
     }
+
+    /// Whether the stream is finished for good: nothing is running
+    /// or waiting to run, and no dag node is still blocked on dependencies
+    /// that could later unblock it.
+    fn is_idle(&mut self) -> Async<bool> {
+        let graph = match self.inner.poll_lock() {
+            Async::Ready(graph) => graph,
+            Async::NotReady => return Async::NotReady
+        };
+
+        Async::Ready(
+            self.queue.is_empty()
+                && self.backlog.is_empty()
+                && self.done.is_empty()
+                && graph.resolved.is_empty()
+                && graph.dag.is_empty()
+        )
+    }
+
+    /// Poisons the transitive descendants of a failed node: they are
+    /// removed from the dag (so they never become ready) and returned so
+    /// the caller can report them as skipped. Unrelated branches are left
+    /// untouched. `None` means the graph lock was contended and nothing was
+    /// done — the caller must retry rather than treat it as "no descendants",
+    /// or a failure isolated under contention would leak its descendants as
+    /// permanently-`Pending` nodes that are never reported as skipped.
+    fn fail(&mut self, index: &Index<I>) -> Option<Vec<Index<I>>> {
+        let mut graph = match self.inner.poll_lock() {
+            Async::Ready(graph) => graph,
+            Async::NotReady => return None
+        };
+
+        let mut frontier: Vec<Index<I>> = graph.dag.walk(index).collect();
+        graph.dag.remove_node(index);
+        graph.cancel_flags.remove(index);
+
+        let mut skipped = Vec::new();
+        while let Some(idx) = frontier.pop() {
+            if !graph.dag.contains(&idx) {
+                continue;
+            }
+            frontier.extend(graph.dag.walk(&idx));
+            graph.dag.remove_node(&idx);
+            graph.cancel_flags.remove(&idx);
+            skipped.push(idx);
+        }
+
+        Some(skipped)
+    }
+
+    /// If `index` was added with a memoization key, records its now-known
+    /// output to be folded into the cache. The actual `Weight`-bounded
+    /// insert happens lazily, the next time the owning [`TaskGraph`] checks
+    /// the cache via `add_task_with_memoized` — so this step, and therefore
+    /// `Execute`'s `Stream` impl, never needs `T::Item: Weight` itself.
+    fn memoize(&mut self, index: &Index<I>, item: &T::Item) {
+        let mut graph = match self.inner.poll_lock() {
+            Async::Ready(graph) => graph,
+            Async::NotReady => return
+        };
+
+        if let Some(key) = graph.keys.remove(index) {
+            graph.pending_inserts.push((key, item.clone()));
+        }
+    }
 }
 
-impl<F, I, S> Stream for Execute<F, I, S>
+impl<F, I, S, K> Stream for Execute<F, I, S, K>
 where
     F: Future,
+    F::Item: Clone,
     for<'i> &'i I: Add<I>,
     for<'i> <&'i I as Add<I>>::Output: Into<I>,
     I: From<u32> + Hash + PartialEq + Eq + Clone,
     S: BuildHasher
 {
-    type Item = (Index<I>, F::Item);
-    type Error = F::Error;
+    type Item = Outcome<I, F>;
+    type Error = ();
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
         match self.rx.poll() {
@@ -174,51 +605,148 @@ where
             Ok(Async::Ready(())) | Err(_) => return Ok(Async::Ready(None))
         }
 
+        if let Some((index, err)) = self.pending_fail.take() {
+            return match self.fail(&index) {
+                Some(skipped) => {
+                    if !skipped.is_empty() {
+                        self.outbox.push_back(Outcome::Skipped(skipped));
+                    }
+                    Ok(Async::Ready(Some(Outcome::Failed(index, err))))
+                },
+                None => {
+                    self.pending_fail = Some((index, err));
+                    Ok(Async::NotReady)
+                }
+            };
+        }
+
+        if let Some(outcome) = self.outbox.pop_front() {
+            return Ok(Async::Ready(Some(outcome)));
+        }
+
         // TODO keep poll ?
         let _ = self.enqueue();
 
-        match self.queue.poll() {
-            Ok(Async::Ready(Some((i, item)))) => {
-                self.done.push(i.clone());
-                Ok(Async::Ready(Some((i, item))))
-            },
-            Ok(Async::Ready(None)) | Ok(Async::NotReady) => Ok(Async::NotReady),
-            Err(err) => Err(err)
+        loop {
+            match self.queue.poll() {
+                Ok(Async::Ready(Some(Some((i, item))))) => {
+                    // A slot just freed up; backfill it from the backlog right away.
+                    let _ = self.enqueue();
+                    self.memoize(&i, &item);
+                    self.done.push((i.clone(), item.clone()));
+                    return Ok(Async::Ready(Some(Outcome::Done(i, item))));
+                },
+                Ok(Async::Ready(Some(None))) => {
+                    // A cancelled node's future was dropped instead of run to
+                    // completion; nothing to report, just refill the freed slot.
+                    let _ = self.enqueue();
+                    continue;
+                },
+                Ok(Async::Ready(None)) => {
+                    // `queue` has nothing left in flight. A graph made up
+                    // entirely of cache hits never puts anything in `queue`
+                    // at all, so flush any outcome `enqueue` just buffered
+                    // before considering the stream idle.
+                    if let Some(outcome) = self.outbox.pop_front() {
+                        return Ok(Async::Ready(Some(outcome)));
+                    }
+                    return match self.is_idle() {
+                        Async::Ready(true) => Ok(Async::Ready(None)),
+                        _ => Ok(Async::NotReady)
+                    };
+                },
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err((i, err)) => {
+                    return match self.fail(&i) {
+                        Some(skipped) => {
+                            if !skipped.is_empty() {
+                                self.outbox.push_back(Outcome::Skipped(skipped));
+                            }
+                            Ok(Async::Ready(Some(Outcome::Failed(i, err))))
+                        },
+                        None => {
+                            // The graph lock was contended; retry on the next
+                            // poll instead of reporting a failure whose
+                            // descendants were never actually isolated.
+                            self.pending_fail = Some((i, err));
+                            Ok(Async::NotReady)
+                        }
+                    };
+                }
+            }
         }
     }
 }
 
 struct IndexFuture<F, I> {
     index: Index<I>,
+    priority: u32,
+    cancel: Arc<AtomicBool>,
     fut: F
 }
 
 impl<F, I> IndexFuture<F, I> {
-    pub fn new(index: Index<I>, fut: F) -> IndexFuture<F, I> {
-        IndexFuture { index, fut }
+    pub fn new(index: Index<I>, priority: u32, cancel: Arc<AtomicBool>, fut: F) -> IndexFuture<F, I> {
+        IndexFuture { index, priority, cancel, fut }
+    }
+}
+
+// Ordering (and hence the `BinaryHeap` backlog's pop order) is by
+// `priority` alone, regardless of what `F` or `I` are.
+impl<F, I> PartialEq for IndexFuture<F, I> {
+    fn eq(&self, other: &Self) -> bool {
+        self.priority == other.priority
+    }
+}
+
+impl<F, I> Eq for IndexFuture<F, I> {}
+
+impl<F, I> PartialOrd for IndexFuture<F, I> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<F, I> Ord for IndexFuture<F, I> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority.cmp(&other.priority)
     }
 }
 
 impl<F: Future, I: Clone> Future for IndexFuture<F, I> {
-    type Item = (Index<I>, F::Item);
-    type Error = F::Error;
+    // `None` means the task was cancelled mid-flight: its future was dropped
+    // without being driven to completion, so there is no `F::Item` to report.
+    // This check only runs when something actually calls `poll` on us again,
+    // so cancellation of a future that would otherwise never be repolled
+    // (parked on I/O with no other waker) is only made effective the next
+    // time it happens to be polled, not forced immediately.
+    type Item = Option<(Index<I>, F::Item)>;
+    type Error = (Index<I>, F::Error);
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if self.cancel.load(AtomicOrdering::Relaxed) {
+            return Ok(Async::Ready(None));
+        }
+
         match self.fut.poll() {
-            Ok(Async::Ready(item)) => Ok(Async::Ready((self.index.clone(), item))),
+            Ok(Async::Ready(item)) => Ok(Async::Ready(Some((self.index.clone(), item)))),
             Ok(Async::NotReady) => Ok(Async::NotReady),
-            Err(err) => Err(err)
+            Err(err) => Err((self.index.clone(), err))
         }
     }
 }
 
-struct TaskWalker<'a, T, I, S> {
-    dag: &'a mut Graph<State<T>, I, S>,
-    walker: IntoIter<Index<I>>
+struct TaskWalker<'a, T: Future, I, S> {
+    dag: &'a mut Graph<State<T, I>, I, S>,
+    walker: IntoIter<Index<I>>,
+    parent: Index<I>,
+    item: T::Item
 }
 
 impl<'a, T, I, S> Iterator for TaskWalker<'a, T, I, S>
 where
+    T: Future,
+    T::Item: Clone,
     for<'i> &'i I: Add<I>,
     for<'i> <&'i I as Add<I>>::Output: Into<I>,
     I: From<u32> + Hash + PartialEq + Eq + Clone,
@@ -233,8 +761,20 @@ where
                 None => continue
             };
 
-            if let State::Pending { count, .. } = state {
+            if let State::Pending { count, order, inputs, .. } = state {
                 *count -= 1;
+                // Look for the first still-empty slot belonging to this
+                // parent, not just the first occurrence of it in `order`:
+                // a node depended on twice (`deps = [a, a]`) is walked twice
+                // from `a`'s single completion (once per edge), and each
+                // walk must land in its own slot or one would stay `None`
+                // and panic the `expect` below once the node is built.
+                let pos = order.iter()
+                    .zip(inputs.iter())
+                    .position(|(dep, input)| dep == &self.parent && input.is_none());
+                if let Some(pos) = pos {
+                    inputs[pos] = Some(self.item.clone());
+                }
             }
 
             match state {
@@ -242,11 +782,87 @@ where
                 _ => continue
             }
 
-            if let State::Pending { task, .. } = mem::replace(state, State::Running) {
-                return Some(IndexFuture::new(index, task));
+            if let State::Pending { priority, inputs, builder, cancel, .. } = mem::replace(state, State::Running) {
+                let inputs = inputs.into_iter()
+                    .map(|input| input.expect("all dependencies resolved before a node becomes ready"))
+                    .collect();
+                return Some(IndexFuture::new(index, priority, cancel, builder(inputs)));
             }
         }
 
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::{ ok, err, FutureResult };
+
+    fn outcomes<T: Future>(execute: Execute<T>) -> Vec<Outcome<u32, T>>
+    where
+        T::Item: Clone
+    {
+        execute.wait().map(|r| r.expect("Execute's Stream::Error is ()")).collect()
+    }
+
+    #[test]
+    fn duplicate_dependencies_each_fill_their_own_input_slot() {
+        let mut graph: TaskGraph<FutureResult<u32, ()>> = TaskGraph::new();
+        let a = graph.add_task(&[], ok(1));
+        graph.add_task_with(&[a.clone(), a.clone()], |inputs| {
+            assert_eq!(inputs, vec![1, 1]);
+            ok(inputs.into_iter().sum())
+        });
+
+        let (_add, execute) = graph.execute();
+        let done: Vec<u32> = outcomes(execute).into_iter()
+            .filter_map(|o| match o {
+                Outcome::Done(_, item) => Some(item),
+                _ => None
+            })
+            .collect();
+        assert_eq!(done, vec![1, 2]);
+    }
+
+    #[test]
+    fn failure_reports_descendants_as_skipped() {
+        let mut graph: TaskGraph<FutureResult<u32, ()>> = TaskGraph::new();
+        let a = graph.add_task(&[], err(()));
+        let b = graph.add_task_with(&[a.clone()], |_| ok(0));
+        graph.add_task_with(&[b], |_| ok(0));
+
+        let (_add, execute) = graph.execute();
+        let mut failed = false;
+        let mut skipped = Vec::new();
+        for outcome in outcomes(execute) {
+            match outcome {
+                Outcome::Failed(index, ()) => {
+                    assert_eq!(index, a);
+                    failed = true;
+                },
+                Outcome::Skipped(indices) => skipped = indices,
+                Outcome::Done(..) => panic!("no node should complete once its ancestor failed")
+            }
+        }
+        assert!(failed);
+        assert_eq!(skipped.len(), 2);
+    }
+
+    #[test]
+    fn priority_admits_highest_first_under_a_bounded_limit() {
+        let mut graph: TaskGraph<FutureResult<u32, ()>> = TaskGraph::new();
+        graph.add_task_prioritized(&[], 1, ok(1));
+        graph.add_task_prioritized(&[], 5, ok(5));
+        graph.add_task_prioritized(&[], 3, ok(3));
+
+        let (_add, execute) = graph.execute_bounded(1);
+        let done: Vec<u32> = outcomes(execute).into_iter()
+            .filter_map(|o| match o {
+                Outcome::Done(_, item) => Some(item),
+                _ => None
+            })
+            .collect();
+        assert_eq!(done, vec![5, 3, 1]);
+    }
+}